@@ -0,0 +1,484 @@
+#![allow(clippy::needless_return)]
+
+use crate::formats::{DbFormat, RecordType};
+use crate::utils::LoadMode;
+use std::{
+  collections::BTreeMap, env, fmt, fs, path::Path, str::FromStr, sync::OnceLock, time::Duration,
+};
+
+// The settings, each one can be set with an environment variable or with a key in the CONFIG_FILE
+pub const CONFIG_VARS: &[&str] = &[
+  "ACCESS_LOG_FORMAT",
+  "ALLOW_CONFIG_DUMP",
+  "ALLOWED_COUNTRIES",
+  "ALLOW_DB_DOWNLOAD",
+  "ALLOW_RELOAD",
+  "ALLOW_SHUTDOWN",
+  "API_KEY",
+  "ASN_DATABASE_PATH",
+  "BASIC_AUTH",
+  "CA_BUNDLE",
+  "CLIENT_TIMEOUT",
+  "COALESCE_LOOKUPS",
+  "CONFIG_FILE",
+  "CORS_ALLOWED_METHODS",
+  "CORS_ALLOWED_ORIGINS",
+  "CORS_EXPOSE_HEADERS",
+  "CORS_MAX_AGE",
+  "DANGER_ACCEPT_INVALID_CERTS",
+  "DATA_DIR",
+  "DATA_FILE_PREFIX",
+  "DB_FORMAT",
+  "DEFAULT_LANG",
+  "DOWNLOAD_PROXY",
+  "DOWNLOAD_USER_AGENT",
+  "ENABLE_DNS_LOOKUP",
+  "ENABLE_TRACING",
+  "HOST",
+  "HTTP2_CLEARTEXT",
+  "KEEP_ALIVE",
+  "LISTEN_BACKLOG",
+  "LISTEN_SOCKET",
+  "LOAD_MODE",
+  "LOOKUP_RECORD_TYPE",
+  "MAX_CONCURRENT_REQUESTS",
+  "MAX_DB_AGE",
+  "MAX_RESPONSE_BYTES",
+  "MAXMIND_DB_NO_CACHE",
+  "MAXMIND_DB_TIMEOUT",
+  "MAXMIND_DB_UPDATE_JITTER",
+  "MAXMIND_DB_URL",
+  "MAXMIND_DB_URL_FALLBACK",
+  "NOT_FOUND_CACHE_MAX_AGE",
+  "NOT_FOUND_STATUS",
+  "OFFLINE_MODE",
+  "OUTPUT_TEMPLATE",
+  "PORT",
+  "REQUEST_TIMEOUT",
+  "RESPONSE_CACHE_MAX_AGE",
+  "ROUTE_PREFIX",
+  "RUST_LOG",
+  "SLOW_LOOKUP_THRESHOLD",
+  "STARTUP_RETRY",
+  "SUMMARY_LOG_INTERVAL",
+  "TEMP_DIR",
+  "TLS_CERT",
+  "TLS_KEY",
+  "VALIDATE_ONLY",
+  "WARM_CACHE",
+  "WEBHOOK_URL",
+  "WITHIN_LIMIT",
+];
+
+// Everything is parsed and validated at startup, so the rest of the program never has to deal with invalid values
+pub struct Config {
+  pub access_log_format: String,
+  pub allow_config_dump: bool,
+  pub allowed_countries: Option<Vec<String>>,
+  pub allow_db_download: bool,
+  pub allow_reload: bool,
+  pub allow_shutdown: bool,
+  pub api_key: Option<String>,
+  pub asn_database_path: Option<String>,
+  pub basic_auth: Option<String>,
+  pub ca_bundle: Option<String>,
+  pub client_timeout: Option<Duration>,
+  pub coalesce_lookups: bool,
+  pub cors_allowed_methods: Vec<String>,
+  pub cors_allowed_origins: Option<Vec<String>>,
+  pub cors_expose_headers: Vec<String>,
+  pub cors_max_age: usize,
+  pub danger_accept_invalid_certs: bool,
+  pub data_dir: String,
+  pub data_file_prefix: String,
+  pub db_format: DbFormat,
+  pub default_lang: Option<String>,
+  pub download_proxy: Option<String>,
+  pub download_user_agent: String,
+  pub enable_dns_lookup: bool,
+  pub enable_tracing: bool,
+  pub hosts: Vec<String>,
+  pub http2_cleartext: bool,
+  pub keep_alive: Option<Duration>,
+  pub listen_backlog: Option<u32>,
+  pub listen_socket: Option<String>,
+  pub load_mode: LoadMode,
+  pub lookup_record_type: RecordType,
+  pub max_concurrent_requests: Option<usize>,
+  pub max_db_age: Option<i64>,
+  pub max_response_bytes: Option<usize>,
+  pub maxmind_db_no_cache: bool,
+  pub maxmind_db_timeout: Duration,
+  pub maxmind_db_update_jitter: f64,
+  pub maxmind_db_url: Vec<String>,
+  pub maxmind_db_url_fallback: Vec<String>,
+  pub not_found_cache_max_age: u64,
+  pub not_found_status: u16,
+  pub offline_mode: bool,
+  pub output_template: Option<String>,
+  pub port: u16,
+  pub request_timeout: Option<Duration>,
+  pub response_cache_max_age: Option<u64>,
+  pub route_prefix: String,
+  pub rust_log: Option<String>,
+  pub slow_lookup_threshold: Option<Duration>,
+  pub startup_retry: Duration,
+  pub summary_log_interval: Option<Duration>,
+  pub temp_dir: Option<String>,
+  pub tls_cert: Option<String>,
+  pub tls_key: Option<String>,
+  pub validate_only: bool,
+  pub warm_cache: bool,
+  pub webhook_url: Option<String>,
+  pub within_limit: usize,
+  // The values as they were given, for /config
+  values: BTreeMap<&'static str, String>,
+}
+
+// The raw values by name, with helpers that turn them into the types that Config uses
+struct Values(BTreeMap<&'static str, String>);
+
+impl Values {
+  fn string(&self, name: &str) -> Option<String> {
+    return self.0.get(name).cloned();
+  }
+
+  fn flag(&self, name: &str) -> bool {
+    return self.0.get(name).is_some_and(|v| v == "true" || v == "1");
+  }
+
+  // A comma-separated list, empty entries are ignored
+  fn list(&self, name: &str) -> Option<Vec<String>> {
+    let value = self.0.get(name)?;
+    return Some(
+      value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect(),
+    );
+  }
+
+  fn parse<T>(&self, name: &str) -> Result<Option<T>, String>
+  where
+    T: FromStr,
+    T::Err: fmt::Display,
+  {
+    let value = match self.0.get(name) {
+      Some(v) => v,
+      None => return Ok(None),
+    };
+    return match value.parse::<T>() {
+      Ok(v) => Ok(Some(v)),
+      Err(err) => Err(format!("invalid {}: {}: {}", name, value, err)),
+    };
+  }
+
+  fn seconds(&self, name: &str) -> Result<Option<Duration>, String> {
+    return Ok(self.parse::<u64>(name)?.map(Duration::from_secs));
+  }
+}
+
+impl Config {
+  // Reads the environment variables and the file in CONFIG_FILE, if it is set, and returns the keys in the file that are unknown
+  // This runs before the logger is set up, so it doesn't log anything itself
+  pub fn load() -> Result<(Config, Vec<String>), String> {
+    let (mut values, unknown) = match env::var("CONFIG_FILE") {
+      Ok(path) => read_config_file(Path::new(&path))?,
+      Err(_) => (BTreeMap::new(), Vec::new()),
+    };
+    // Environment variables take precedence over the file
+    for name in CONFIG_VARS {
+      if let Ok(v) = env::var(name) {
+        values.insert(name, v);
+      }
+    }
+    return Ok((Config::from_values(values)?, unknown));
+  }
+
+  pub fn from_values(values: BTreeMap<&'static str, String>) -> Result<Config, String> {
+    let values = Values(values);
+
+    let data_dir = match values.string("DATA_DIR") {
+      Some(v) => v,
+      None => return Err("DATA_DIR is not set".to_string()),
+    };
+
+    let not_found_status = values.parse::<u16>("NOT_FOUND_STATUS")?.unwrap_or(404);
+    if not_found_status != 404 && not_found_status != 204 {
+      return Err(format!(
+        "invalid NOT_FOUND_STATUS: {} (expected 404 or 204)",
+        not_found_status
+      ));
+    }
+
+    // NaN and infinity parse as f64 too, they are rejected by the range check
+    let maxmind_db_update_jitter = values
+      .parse::<f64>("MAXMIND_DB_UPDATE_JITTER")?
+      .unwrap_or(10.0);
+    if !(0.0..=100.0).contains(&maxmind_db_update_jitter) {
+      return Err(format!(
+        "invalid MAXMIND_DB_UPDATE_JITTER: {} (expected a percentage between 0 and 100)",
+        maxmind_db_update_jitter
+      ));
+    }
+
+    let tls_cert = values.string("TLS_CERT");
+    let tls_key = values.string("TLS_KEY");
+    if tls_cert.is_some() != tls_key.is_some() {
+      return Err("both TLS_CERT and TLS_KEY must be configured to enable TLS".to_string());
+    }
+
+    // Set ROUTE_PREFIX to serve the API under a base path, e.g. ROUTE_PREFIX=/geoip
+    let route_prefix = match values.string("ROUTE_PREFIX") {
+      Some(v) if !v.trim_matches('/').is_empty() => format!("/{}", v.trim_matches('/')),
+      _ => String::new(),
+    };
+
+    return Ok(Config {
+      access_log_format: values.string("ACCESS_LOG_FORMAT").unwrap_or(String::from(
+        r#"%{r}a "%r" %s %b "%{Origin}i" "%{User-Agent}i" %T %{x-request-id}o"#,
+      )),
+      allow_config_dump: values.flag("ALLOW_CONFIG_DUMP"),
+      allowed_countries: values.list("ALLOWED_COUNTRIES"),
+      allow_db_download: values.flag("ALLOW_DB_DOWNLOAD"),
+      allow_reload: values.flag("ALLOW_RELOAD"),
+      allow_shutdown: values.flag("ALLOW_SHUTDOWN"),
+      api_key: values.string("API_KEY"),
+      asn_database_path: values.string("ASN_DATABASE_PATH"),
+      basic_auth: values.string("BASIC_AUTH"),
+      ca_bundle: values.string("CA_BUNDLE"),
+      client_timeout: values.seconds("CLIENT_TIMEOUT")?,
+      coalesce_lookups: values.flag("COALESCE_LOOKUPS"),
+      cors_allowed_methods: values
+        .list("CORS_ALLOWED_METHODS")
+        .unwrap_or(vec!["GET".to_string()]),
+      cors_allowed_origins: values.list("CORS_ALLOWED_ORIGINS"),
+      cors_expose_headers: values.list("CORS_EXPOSE_HEADERS").unwrap_or(vec![
+        "server".to_string(),
+        "x-maxmind-build-epoch".to_string(),
+      ]),
+      cors_max_age: values.parse("CORS_MAX_AGE")?.unwrap_or(3600),
+      danger_accept_invalid_certs: values.flag("DANGER_ACCEPT_INVALID_CERTS"),
+      data_dir,
+      data_file_prefix: values.string("DATA_FILE_PREFIX").unwrap_or_default(),
+      db_format: values.parse("DB_FORMAT")?.unwrap_or(DbFormat::MaxMind),
+      default_lang: values.string("DEFAULT_LANG"),
+      download_proxy: values.string("DOWNLOAD_PROXY"),
+      download_user_agent: values.string("DOWNLOAD_USER_AGENT").unwrap_or(format!(
+        "maxmind-geoip-api/{}",
+        option_env!("CARGO_PKG_VERSION").unwrap_or("unknown")
+      )),
+      enable_dns_lookup: values.flag("ENABLE_DNS_LOOKUP"),
+      enable_tracing: values.flag("ENABLE_TRACING"),
+      hosts: values.list("HOST").unwrap_or(vec!["0.0.0.0".to_string()]),
+      http2_cleartext: values.flag("HTTP2_CLEARTEXT"),
+      keep_alive: values.seconds("KEEP_ALIVE")?,
+      listen_backlog: values.parse("LISTEN_BACKLOG")?,
+      listen_socket: values.string("LISTEN_SOCKET"),
+      load_mode: values.parse("LOAD_MODE")?.unwrap_or(LoadMode::Mmap),
+      lookup_record_type: values
+        .parse("LOOKUP_RECORD_TYPE")?
+        .unwrap_or(RecordType::City),
+      max_concurrent_requests: values.parse("MAX_CONCURRENT_REQUESTS")?,
+      max_db_age: values.parse("MAX_DB_AGE")?,
+      max_response_bytes: values.parse("MAX_RESPONSE_BYTES")?,
+      maxmind_db_no_cache: values.flag("MAXMIND_DB_NO_CACHE"),
+      maxmind_db_timeout: values
+        .seconds("MAXMIND_DB_TIMEOUT")?
+        .unwrap_or(Duration::from_secs(60)),
+      maxmind_db_update_jitter,
+      maxmind_db_url: values.list("MAXMIND_DB_URL").unwrap_or_default(),
+      maxmind_db_url_fallback: values.list("MAXMIND_DB_URL_FALLBACK").unwrap_or_default(),
+      not_found_cache_max_age: values.parse("NOT_FOUND_CACHE_MAX_AGE")?.unwrap_or(0),
+      not_found_status,
+      offline_mode: values.flag("OFFLINE_MODE"),
+      output_template: values.string("OUTPUT_TEMPLATE"),
+      port: values.parse("PORT")?.unwrap_or(3000),
+      request_timeout: values.seconds("REQUEST_TIMEOUT")?,
+      response_cache_max_age: values.parse("RESPONSE_CACHE_MAX_AGE")?,
+      route_prefix,
+      rust_log: values.string("RUST_LOG"),
+      slow_lookup_threshold: values
+        .parse::<u64>("SLOW_LOOKUP_THRESHOLD")?
+        .map(Duration::from_millis),
+      startup_retry: values.seconds("STARTUP_RETRY")?.unwrap_or_default(),
+      summary_log_interval: values
+        .parse::<u64>("SUMMARY_LOG_INTERVAL")?
+        .filter(|v| *v > 0)
+        .map(|v| Duration::from_secs(v * 60)),
+      temp_dir: values.string("TEMP_DIR"),
+      tls_cert,
+      tls_key,
+      validate_only: values.flag("VALIDATE_ONLY"),
+      warm_cache: values.flag("WARM_CACHE"),
+      webhook_url: values.string("WEBHOOK_URL"),
+      within_limit: values.parse("WITHIN_LIMIT")?.unwrap_or(1000),
+      values: values.0,
+    });
+  }
+
+  // The value of a setting as it was given, e.g. "1" for a flag
+  pub fn value(&self, name: &str) -> Option<&str> {
+    return self.values.get(name).map(|v| v.as_str());
+  }
+}
+
+fn read_config_file(path: &Path) -> Result<(BTreeMap<&'static str, String>, Vec<String>), String> {
+  let contents =
+    fs::read_to_string(path).map_err(|err| format!("error reading {}: {}", path.display(), err))?;
+  return parse_config_file(&contents)
+    .map_err(|err| format!("error parsing {}: {}", path.display(), err));
+}
+
+// Keys are the environment variable names, case-insensitive, e.g. maxmind_db_url = "https://..."
+fn parse_config_file(
+  contents: &str,
+) -> Result<(BTreeMap<&'static str, String>, Vec<String>), String> {
+  let table: toml::Table = toml::from_str(contents).map_err(|err| err.to_string())?;
+
+  let mut values = BTreeMap::new();
+  let mut unknown = Vec::new();
+  for (key, value) in table {
+    let name = match CONFIG_VARS.iter().find(|v| v.eq_ignore_ascii_case(&key)) {
+      Some(name) => *name,
+      None => {
+        unknown.push(key);
+        continue;
+      }
+    };
+    let value = match value {
+      toml::Value::String(v) => v,
+      toml::Value::Integer(v) => v.to_string(),
+      toml::Value::Float(v) => v.to_string(),
+      toml::Value::Boolean(v) => v.to_string(),
+      // Lists are joined with commas, e.g. allowed_countries = ["US", "CA"]
+      toml::Value::Array(values) => values
+        .iter()
+        .map(|v| match v {
+          toml::Value::String(v) => v.clone(),
+          v => v.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(","),
+      _ => return Err(format!("unsupported value for {}", key)),
+    };
+    values.insert(name, value);
+  }
+  return Ok((values, unknown));
+}
+
+fn config_cell() -> &'static OnceLock<Config> {
+  static CONFIG: OnceLock<Config> = OnceLock::new();
+  &CONFIG
+}
+
+// Called once by main before the runtime is started
+pub fn init(config: Config) {
+  if config_cell().set(config).is_err() {
+    panic!("the config has already been loaded");
+  }
+}
+
+pub fn get() -> &'static Config {
+  return config_cell().get().expect("the config has not been loaded");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn from_values(values: &[(&'static str, &str)]) -> Result<Config, String> {
+    return Config::from_values(
+      values
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect(),
+    );
+  }
+
+  #[test]
+  fn defaults() {
+    let config = from_values(&[("DATA_DIR", "/data")]).unwrap();
+    assert_eq!(config.data_dir, "/data");
+    assert_eq!(config.port, 3000);
+    assert_eq!(config.hosts, vec!["0.0.0.0"]);
+    assert_eq!(config.within_limit, 1000);
+    assert_eq!(config.not_found_status, 404);
+    assert_eq!(config.route_prefix, "");
+    assert!(!config.allow_reload);
+    assert!(config.allowed_countries.is_none());
+    assert!(config.db_format == DbFormat::MaxMind);
+    assert!(config.lookup_record_type == RecordType::City);
+  }
+
+  #[test]
+  fn typed_values() {
+    let config = from_values(&[
+      ("ALLOW_RELOAD", "true"),
+      ("ALLOWED_COUNTRIES", "US, CA,"),
+      ("DATA_DIR", "/data"),
+      ("DB_FORMAT", "ipinfo"),
+      ("PORT", "8080"),
+      ("ROUTE_PREFIX", "/geoip/"),
+      ("SLOW_LOOKUP_THRESHOLD", "50"),
+      ("SUMMARY_LOG_INTERVAL", "0"),
+    ])
+    .unwrap();
+    assert!(config.allow_reload);
+    assert_eq!(
+      config.allowed_countries,
+      Some(vec!["US".to_string(), "CA".to_string()])
+    );
+    assert!(config.db_format == DbFormat::Ipinfo);
+    assert_eq!(config.port, 8080);
+    assert_eq!(config.route_prefix, "/geoip");
+    assert_eq!(
+      config.slow_lookup_threshold,
+      Some(Duration::from_millis(50))
+    );
+    assert_eq!(config.summary_log_interval, None);
+    assert_eq!(config.value("PORT"), Some("8080"));
+  }
+
+  #[test]
+  fn invalid_values() {
+    assert!(from_values(&[]).is_err());
+    for (name, value) in [
+      ("PORT", "http"),
+      ("MAX_DB_AGE", "a week"),
+      ("DB_FORMAT", "geoip"),
+      ("LOAD_MODE", "disk"),
+      ("LOOKUP_RECORD_TYPE", "isp"),
+      ("NOT_FOUND_STATUS", "200"),
+      ("MAXMIND_DB_UPDATE_JITTER", "NaN"),
+      ("MAXMIND_DB_UPDATE_JITTER", "inf"),
+      ("MAXMIND_DB_UPDATE_JITTER", "-5"),
+      ("MAXMIND_DB_UPDATE_JITTER", "150"),
+      ("TLS_CERT", "cert.pem"),
+    ] {
+      let result = from_values(&[("DATA_DIR", "/data"), (name, value)]);
+      assert!(result.is_err(), "{}={} should be invalid", name, value);
+    }
+  }
+
+  #[test]
+  fn config_file() {
+    let (values, unknown) = parse_config_file(
+      r#"
+        maxmind_db_url = "https://example.com/db.tar.gz"
+        allowed_countries = ["US", "CA"]
+        PORT = 8080
+        allow_reload = true
+        unknown_key = "x"
+      "#,
+    )
+    .unwrap();
+    assert_eq!(values["MAXMIND_DB_URL"], "https://example.com/db.tar.gz");
+    assert_eq!(values["ALLOWED_COUNTRIES"], "US,CA");
+    assert_eq!(values["PORT"], "8080");
+    assert_eq!(values["ALLOW_RELOAD"], "true");
+    assert_eq!(unknown, vec!["unknown_key"]);
+    assert!(parse_config_file("port = {}").is_err());
+  }
+}
@@ -0,0 +1,341 @@
+use crate::config;
+use log::{error, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fs, process, str::FromStr, sync::OnceLock};
+
+// Which vendor's mmdb layout the database uses, configured with DB_FORMAT
+#[derive(Clone, Copy, PartialEq)]
+pub enum DbFormat {
+  MaxMind,
+  // DB-IP uses the same layout as the GeoIP2 City database
+  DbIp,
+  Ipinfo,
+}
+
+impl FromStr for DbFormat {
+  type Err = &'static str;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "maxmind" => Ok(DbFormat::MaxMind),
+      "dbip" => Ok(DbFormat::DbIp),
+      "ipinfo" => Ok(DbFormat::Ipinfo),
+      _ => Err("expected maxmind, dbip, or ipinfo"),
+    }
+  }
+}
+
+// Which record the /{ip} endpoint looks up, configured with LOOKUP_RECORD_TYPE
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordType {
+  City,
+  Country,
+  Enterprise,
+  Asn,
+}
+
+impl RecordType {
+  pub fn name(&self) -> &'static str {
+    match self {
+      RecordType::City => "city",
+      RecordType::Country => "country",
+      RecordType::Enterprise => "enterprise",
+      RecordType::Asn => "asn",
+    }
+  }
+
+  // The part of the database_type that the matching MaxMind databases have in common, e.g. GeoLite2-City
+  fn database_type(&self) -> &'static str {
+    match self {
+      RecordType::City => "City",
+      RecordType::Country => "Country",
+      RecordType::Enterprise => "Enterprise",
+      RecordType::Asn => "ASN",
+    }
+  }
+}
+
+impl FromStr for RecordType {
+  type Err = &'static str;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "city" => Ok(RecordType::City),
+      "country" => Ok(RecordType::Country),
+      "enterprise" => Ok(RecordType::Enterprise),
+      "asn" => Ok(RecordType::Asn),
+      _ => Err("expected city, country, enterprise, or asn"),
+    }
+  }
+}
+
+// Only a warning since other vendors name their databases differently
+pub fn check_record_type(database_type: &str) {
+  let record_type = config::get().lookup_record_type;
+  if config::get().db_format == DbFormat::MaxMind
+    && !database_type.contains(record_type.database_type())
+  {
+    warn!(
+      "LOOKUP_RECORD_TYPE is {} but the database type is {}, lookups will likely be incomplete",
+      record_type.name(),
+      database_type
+    );
+  }
+}
+
+// Depending on the database, IPinfo stores coordinates as either numbers or strings
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Coordinate {
+  Number(f64),
+  Text(String),
+}
+
+impl Coordinate {
+  fn value(&self) -> Option<f64> {
+    match self {
+      Coordinate::Number(v) => Some(*v),
+      Coordinate::Text(v) => v.parse().ok(),
+    }
+  }
+}
+
+// IPinfo databases have a flat layout with bare strings, e.g. "country": "US"
+#[derive(Deserialize, Debug)]
+pub struct IpinfoRecord {
+  pub city: Option<String>,
+  pub region: Option<String>,
+  pub country: Option<String>,
+  pub country_name: Option<String>,
+  pub continent: Option<String>,
+  pub continent_name: Option<String>,
+  pub latitude: Option<Coordinate>,
+  pub longitude: Option<Coordinate>,
+  pub postal_code: Option<String>,
+  pub timezone: Option<String>,
+}
+
+impl IpinfoRecord {
+  // Converts the record to the same layout as a GeoIP2 City record
+  pub fn to_geoip2(&self) -> Value {
+    let names = |name: &Option<String>| name.as_ref().map(|v| json!({ "en": v }));
+    let latitude = self.latitude.as_ref().and_then(|v| v.value());
+    let longitude = self.longitude.as_ref().and_then(|v| v.value());
+
+    let mut record = json!({});
+    if self.city.is_some() {
+      record["city"] = json!({ "names": names(&self.city) });
+    }
+    if self.continent.is_some() || self.continent_name.is_some() {
+      record["continent"] = json!({
+        "code": self.continent,
+        "names": names(&self.continent_name),
+      });
+    }
+    if self.country.is_some() || self.country_name.is_some() {
+      record["country"] = json!({
+        "iso_code": self.country,
+        "names": names(&self.country_name),
+      });
+    }
+    if latitude.is_some() || longitude.is_some() || self.timezone.is_some() {
+      record["location"] = json!({
+        "latitude": latitude,
+        "longitude": longitude,
+        "time_zone": self.timezone,
+      });
+    }
+    if self.postal_code.is_some() {
+      record["postal"] = json!({ "code": self.postal_code });
+    }
+    if self.region.is_some() {
+      record["subdivisions"] = json!([{ "names": names(&self.region) }]);
+    }
+    return record;
+  }
+}
+
+// OUTPUT_TEMPLATE is the path to a JSON file that maps output keys to fields in the lookup response, using dots between the keys
+// For example {"country_code": "country.iso_code", "city": "city.names.en", "region": "subdivisions.0.iso_code"}
+pub fn output_template() -> Option<&'static BTreeMap<String, String>> {
+  static OUTPUT_TEMPLATE: OnceLock<Option<BTreeMap<String, String>>> = OnceLock::new();
+  OUTPUT_TEMPLATE
+    .get_or_init(|| {
+      let path = config::get().output_template.as_ref()?;
+      let template = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|v| serde_json::from_str(&v).map_err(|err| err.to_string()));
+      match template {
+        Ok(template) => Some(template),
+        Err(err) => {
+          error!("Error loading OUTPUT_TEMPLATE {}: {}", path, err);
+          process::exit(1);
+        }
+      }
+    })
+    .as_ref()
+}
+
+// Fields that are missing from the response are null
+pub fn apply_output_template(template: &BTreeMap<String, String>, body: &Value) -> Value {
+  let output: serde_json::Map<String, Value> = template
+    .iter()
+    .map(|(key, path)| {
+      let pointer = format!("/{}", path.replace('.', "/"));
+      let value = body.pointer(&pointer).cloned().unwrap_or(Value::Null);
+      (key.clone(), value)
+    })
+    .collect();
+  return Value::Object(output);
+}
+
+// The response formats of the /{ip} endpoint
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+  Json,
+  GeoJson,
+  Xml,
+}
+
+impl OutputFormat {
+  fn from_param(format: &str) -> Option<OutputFormat> {
+    match format {
+      "json" => Some(OutputFormat::Json),
+      "geojson" => Some(OutputFormat::GeoJson),
+      "xml" => Some(OutputFormat::Xml),
+      _ => None,
+    }
+  }
+
+  fn from_media_type(media_type: &str) -> Option<OutputFormat> {
+    match media_type {
+      "application/json" | "application/*" | "*/*" => Some(OutputFormat::Json),
+      "application/geo+json" => Some(OutputFormat::GeoJson),
+      "application/xml" | "text/xml" => Some(OutputFormat::Xml),
+      _ => None,
+    }
+  }
+}
+
+// Picks the response format from ?format= or else the Accept header, JSON if neither is present
+// Returns None if the client only accepts formats that we can't produce, which should be a 406
+pub fn negotiate_format(accept: Option<&str>, format: Option<&str>) -> Option<OutputFormat> {
+  if let Some(format) = format {
+    return OutputFormat::from_param(format);
+  }
+  let accept = match accept.map(|v| v.trim()) {
+    Some(v) if !v.is_empty() => v,
+    _ => return Some(OutputFormat::Json),
+  };
+  let mut media_types: Vec<(f32, String)> = accept
+    .split(',')
+    .filter_map(|v| {
+      let mut parts = v.split(';');
+      let media_type = parts.next()?.trim().to_ascii_lowercase();
+      let quality = match parts.find_map(|v| v.trim().strip_prefix("q=")) {
+        Some(q) => q.parse::<f32>().ok()?,
+        None => 1.0,
+      };
+      if quality <= 0.0 {
+        return None;
+      }
+      Some((quality, media_type))
+    })
+    .collect();
+  // The sort is stable, so media types with the same quality keep the client's order
+  media_types.sort_by(|a, b| b.0.total_cmp(&a.0));
+  let output_format = media_types
+    .iter()
+    .find_map(|(_, media_type)| OutputFormat::from_media_type(media_type))?;
+
+  // Browsers send e.g. text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8 which would otherwise pick XML
+  // So XML is only picked over a wildcard when the client ranks it above application/json explicitly
+  if output_format == OutputFormat::Xml {
+    let wildcard = media_types
+      .iter()
+      .any(|(_, media_type)| media_type == "*/*" || media_type == "application/*");
+    let json = media_types
+      .iter()
+      .any(|(_, media_type)| media_type == "application/json");
+    if wildcard && !json {
+      return Some(OutputFormat::Json);
+    }
+  }
+  return Some(output_format);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ipinfo_to_geoip2() {
+    let record: IpinfoRecord = serde_json::from_value(json!({
+      "city": "Milton",
+      "region": "Washington",
+      "country": "US",
+      "country_name": "United States",
+      "latitude": "47.2513",
+      "longitude": -122.3149,
+      "postal_code": "98354",
+      "timezone": "America/Los_Angeles",
+    }))
+    .unwrap();
+    let record = record.to_geoip2();
+    assert_eq!(record["country"]["iso_code"], "US");
+    assert_eq!(record["subdivisions"][0]["names"]["en"], "Washington");
+    assert_eq!(record["location"]["latitude"], 47.2513);
+    assert_eq!(record["location"]["time_zone"], "America/Los_Angeles");
+    assert_eq!(record["postal"]["code"], "98354");
+    assert_eq!(record["continent"], Value::Null);
+  }
+
+  #[test]
+  fn negotiate_format_default() {
+    assert!(negotiate_format(None, None) == Some(OutputFormat::Json));
+    assert!(negotiate_format(Some(""), None) == Some(OutputFormat::Json));
+    assert!(negotiate_format(Some("*/*"), None) == Some(OutputFormat::Json));
+  }
+
+  #[test]
+  fn negotiate_format_param() {
+    let accept = Some("application/json");
+    assert!(negotiate_format(accept, Some("xml")) == Some(OutputFormat::Xml));
+    assert!(negotiate_format(accept, Some("geojson")) == Some(OutputFormat::GeoJson));
+    assert!(negotiate_format(accept, Some("csv")).is_none());
+  }
+
+  #[test]
+  fn negotiate_format_browser() {
+    let accept = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+    assert!(negotiate_format(Some(accept), None) == Some(OutputFormat::Json));
+    let accept =
+      "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8";
+    assert!(negotiate_format(Some(accept), None) == Some(OutputFormat::Json));
+  }
+
+  #[test]
+  fn negotiate_format_xml() {
+    assert!(negotiate_format(Some("application/xml"), None) == Some(OutputFormat::Xml));
+    assert!(negotiate_format(Some("text/xml"), None) == Some(OutputFormat::Xml));
+    let accept = Some("application/xml, application/json;q=0.5, */*;q=0.1");
+    assert!(negotiate_format(accept, None) == Some(OutputFormat::Xml));
+    let accept = Some("application/json;q=0.5, application/xml");
+    assert!(negotiate_format(accept, None) == Some(OutputFormat::Xml));
+    let accept = Some("application/xml;q=0.5, application/json");
+    assert!(negotiate_format(accept, None) == Some(OutputFormat::Json));
+  }
+
+  #[test]
+  fn negotiate_format_wildcard_tie() {
+    assert!(negotiate_format(Some("application/xml, */*"), None) == Some(OutputFormat::Json));
+    assert!(negotiate_format(Some("*/*, application/xml"), None) == Some(OutputFormat::Json));
+  }
+
+  #[test]
+  fn negotiate_format_not_acceptable() {
+    assert!(negotiate_format(Some("text/html"), None).is_none());
+    assert!(negotiate_format(Some("application/json;q=0"), None).is_none());
+  }
+}
@@ -1,31 +1,81 @@
 #![allow(clippy::needless_return)]
 
-use actix_web::web::Buf;
+use crate::config;
+use futures_util::StreamExt;
 use log::{debug, error, info, warn};
 use std::{
-  env,
   error::Error,
-  fs,
+  fmt, fs,
+  io::{BufReader, Write},
   path::{Path, PathBuf},
   process,
-  sync::OnceLock,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+  },
   time,
 };
 
-pub fn get_env_var(name: &str) -> String {
-  match env::var(name) {
-    Ok(v) => v,
-    Err(err) => {
-      error!("{}: {}", name, err);
-      process::exit(1);
+// The error code when renaming a file across filesystems
+const EXDEV: i32 = 18;
+
+// Why updating the database failed, so that the download counters (and callers) can tell the failures apart
+// NotModified is only used between download_database_from and download_database, which treats it as a success
+#[derive(Debug)]
+pub enum DbError {
+  // The request failed or the server responded with an unexpected status
+  Network(String),
+  // The download couldn't be decompressed or the mmdb file wasn't found in the archive
+  Extract(String),
+  // The extracted file can't be opened as a database
+  Validate(String),
+  NotModified,
+  // The download is a valid database but older than the current one, which is kept
+  NotNewer(String),
+  // There is no URL to download from and no database file
+  NotConfigured(PathBuf),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for DbError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DbError::Network(msg)
+      | DbError::Extract(msg)
+      | DbError::Validate(msg)
+      | DbError::NotNewer(msg) => {
+        write!(f, "{}", msg)
+      }
+      DbError::NotModified => write!(f, "The database file is up to date"),
+      DbError::NotConfigured(path) => write!(
+        f,
+        "Please configure MAXMIND_DB_URL or place a database file at {}",
+        path.display()
+      ),
+      DbError::Io(err) => write!(f, "{}", err),
     }
   }
 }
 
+impl Error for DbError {}
+
+impl From<std::io::Error> for DbError {
+  fn from(err: std::io::Error) -> Self {
+    return DbError::Io(err);
+  }
+}
+
+impl From<reqwest::Error> for DbError {
+  fn from(err: reqwest::Error) -> Self {
+    return DbError::Network(err.to_string());
+  }
+}
+
 pub fn data_dir() -> &'static str {
   static DATA_DIR: OnceLock<String> = OnceLock::new();
   DATA_DIR.get_or_init(|| {
-    let data_dir = get_env_var("DATA_DIR");
+    let data_dir = config::get().data_dir.clone();
 
     match fs::metadata(&data_dir) {
       Ok(metadata) => {
@@ -44,29 +94,176 @@ pub fn data_dir() -> &'static str {
   })
 }
 
+// Checked once by creating and removing a file, since read-only mounts can't be detected from the permissions alone
+pub fn data_dir_writable() -> bool {
+  static DATA_DIR_WRITABLE: OnceLock<bool> = OnceLock::new();
+  *DATA_DIR_WRITABLE.get_or_init(|| {
+    let test_path = Path::new(data_dir()).join(format!(".write-test-{}", process::id()));
+    match fs::File::create(&test_path) {
+      Ok(_) => {
+        let _ = fs::remove_file(&test_path);
+        true
+      }
+      Err(err) => {
+        info!("{} is not writable: {}", data_dir(), err);
+        false
+      }
+    }
+  })
+}
+
+pub fn tls_config() -> Option<rustls::ServerConfig> {
+  let config = config::get();
+  // The config makes sure that either both or neither are set
+  let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+    (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+    _ => return None,
+  };
+
+  match load_tls_config(cert_path, key_path) {
+    Ok(config) => Some(config),
+    Err(err) => {
+      error!("Error loading TLS certificate and key: {}", err);
+      process::exit(1);
+    }
+  }
+}
+
+fn load_tls_config(
+  cert_path: &str,
+  key_path: &str,
+) -> Result<rustls::ServerConfig, Box<dyn Error>> {
+  let mut cert_reader = BufReader::new(fs::File::open(cert_path)?);
+  let mut key_reader = BufReader::new(fs::File::open(key_path)?);
+  let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+  if certs.is_empty() {
+    return Err(format!("no certificates found in {}", cert_path).into());
+  }
+  let key = match rustls_pemfile::private_key(&mut key_reader)? {
+    Some(key) => key,
+    None => return Err(format!("no private key found in {}", key_path).into()),
+  };
+
+  let provider = Arc::new(rustls::crypto::ring::default_provider());
+  let config = rustls::ServerConfig::builder_with_provider(provider)
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+  return Ok(config);
+}
+
+// Scratch space for downloading and extracting the database, defaults to DATA_DIR
+fn temp_dir() -> &'static Path {
+  static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+  TEMP_DIR.get_or_init(|| match &config::get().temp_dir {
+    Some(v) => PathBuf::from(v),
+    None => PathBuf::from(data_dir()),
+  })
+}
+
+// The database and the files that keep track of downloads (stamp, etag, etc.) can be given a prefix with DATA_FILE_PREFIX
+// This allows several instances or databases to share the same directory without overwriting each other's files
+fn sidecar_prefix() -> &'static str {
+  return &config::get().data_file_prefix;
+}
+
+fn sidecar_path(name: &str) -> PathBuf {
+  return Path::new(data_dir()).join(format!("{}{}", sidecar_prefix(), name));
+}
+
+// The database is memory-mapped by default, LOAD_MODE=memory reads the whole file into memory instead
+// mmap can stall or fail on some network filesystems and container overlay setups
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoadMode {
+  Mmap,
+  Memory,
+}
+
+impl FromStr for LoadMode {
+  type Err = &'static str;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "mmap" => Ok(LoadMode::Mmap),
+      "memory" => Ok(LoadMode::Memory),
+      _ => Err("expected mmap or memory"),
+    }
+  }
+}
+
+pub enum DatabaseSource {
+  Mmap(maxminddb::Mmap),
+  Memory(Vec<u8>),
+}
+
+impl AsRef<[u8]> for DatabaseSource {
+  fn as_ref(&self) -> &[u8] {
+    match self {
+      DatabaseSource::Mmap(mmap) => mmap.as_ref(),
+      DatabaseSource::Memory(buf) => buf.as_ref(),
+    }
+  }
+}
+
+// Reads a byte from every page of the mmap so that the first lookups don't have to wait for page faults
+fn warm_cache(buf: &[u8]) {
+  let started = time::Instant::now();
+  let sum = buf
+    .iter()
+    .step_by(4096)
+    .fold(0u8, |sum, v| sum.wrapping_add(*v));
+  std::hint::black_box(sum);
+  info!(
+    "Warmed up the database cache in {} ms",
+    started.elapsed().as_millis()
+  );
+}
+
+pub fn open_database(
+  path: &Path,
+) -> Result<maxminddb::Reader<DatabaseSource>, maxminddb::MaxMindDBError> {
+  let io_error = |err: std::io::Error| maxminddb::MaxMindDBError::IoError(err.to_string());
+  let source = match config::get().load_mode {
+    LoadMode::Memory => DatabaseSource::Memory(fs::read(path).map_err(io_error)?),
+    LoadMode::Mmap => {
+      let file = fs::File::open(path).map_err(io_error)?;
+      // This is the same thing that Reader::open_mmap does
+      // The database files are replaced with a rename, so the mapped file is never modified
+      let mmap = unsafe { maxminddb::Mmap::map(&file) }.map_err(io_error)?;
+      if config::get().warm_cache {
+        warm_cache(&mmap);
+      }
+      DatabaseSource::Mmap(mmap)
+    }
+  };
+  return maxminddb::Reader::from_source(source);
+}
+
 pub fn database_path() -> &'static Path {
   static DATABASE_PATH: OnceLock<PathBuf> = OnceLock::new();
-  DATABASE_PATH.get_or_init(|| Path::new(data_dir()).join("database.mmdb"))
+  DATABASE_PATH.get_or_init(|| sidecar_path("database.mmdb"))
 }
 
-fn save_mmdb(
-  source_path: &Path,
-  temp_path: &Path,
-  destination_path: &Path,
-) -> Result<(), Box<dyn Error>> {
-  // This function pulls out the mmdb file from a bunch of possible compression formats, even combinations that are unlikely
-  // So it needs two temporary files to do this without putting everything in memory
-  // At the end the mmdb file is moved to the destination path, in an atomic operation
-  let mut read_path = source_path;
-  let mut write_path = temp_path;
-
-  loop {
-    let fmt = file_format::FileFormat::from_file(read_path)?;
+// The formats that save_mmdb knows how to extract the mmdb file from
+const DECODERS: [file_format::FileFormat; 6] = [
+  file_format::FileFormat::TapeArchive,
+  file_format::FileFormat::Gzip,
+  file_format::FileFormat::Bzip2,
+  file_format::FileFormat::Zip,
+  file_format::FileFormat::Xz,
+  file_format::FileFormat::Zstandard,
+];
 
-    if fmt == file_format::FileFormat::TapeArchive {
+fn decompress(
+  fmt: &file_format::FileFormat,
+  read_path: &Path,
+  write_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+  let reader = fs::File::open(read_path)?;
+  let mut writer = fs::File::create(write_path)?;
+  match fmt {
+    file_format::FileFormat::TapeArchive => {
       // .tar
-      let reader = fs::File::open(read_path)?;
-      let mut writer = fs::File::create(write_path)?;
       let mut archive = tar::Archive::new(reader);
       let mut found = false;
       for file in archive.entries()? {
@@ -78,8 +275,6 @@ fn save_mmdb(
         }
         if path.ends_with(".mmdb") {
           std::io::copy(&mut file, &mut writer)?;
-          writer.sync_all()?;
-          fs::remove_file(read_path)?;
           found = true;
           break;
         }
@@ -87,26 +282,19 @@ fn save_mmdb(
       if !found {
         return Err("mmdb file not found in archive".into());
       }
-    } else if fmt == file_format::FileFormat::Gzip {
+    }
+    file_format::FileFormat::Gzip => {
       // .gz
-      let reader = fs::File::open(read_path)?;
-      let mut writer = fs::File::create(write_path)?;
       let mut decompressor = flate2::read::GzDecoder::new(reader);
       std::io::copy(&mut decompressor, &mut writer)?;
-      writer.sync_all()?;
-      fs::remove_file(read_path)?;
-    } else if fmt == file_format::FileFormat::Bzip2 {
+    }
+    file_format::FileFormat::Bzip2 => {
       // .bz2
-      let reader = fs::File::open(read_path)?;
-      let mut writer = fs::File::create(write_path)?;
-      let mut decompressor = bzip2::read::BzDecoder::new(reader);
+      let mut decompressor = bzip2::read::MultiBzDecoder::new(reader);
       std::io::copy(&mut decompressor, &mut writer)?;
-      writer.sync_all()?;
-      fs::remove_file(read_path)?;
-    } else if fmt == file_format::FileFormat::Zip {
+    }
+    file_format::FileFormat::Zip => {
       // .zip
-      let reader = fs::File::open(read_path)?;
-      let mut writer = fs::File::create(write_path)?;
       let mut archive = zip::ZipArchive::new(reader)?;
       let mut found = false;
       for i in 0..archive.len() {
@@ -117,8 +305,6 @@ fn save_mmdb(
         }
         if name.ends_with(".mmdb") {
           std::io::copy(&mut file, &mut writer)?;
-          writer.sync_all()?;
-          fs::remove_file(read_path)?;
           found = true;
           break;
         }
@@ -126,80 +312,283 @@ fn save_mmdb(
       if !found {
         return Err("mmdb file not found in archive".into());
       }
-    } else if fmt == file_format::FileFormat::Xz {
+    }
+    file_format::FileFormat::Xz => {
       // .xz
-      let reader = fs::File::open(read_path)?;
-      let mut writer = fs::File::create(write_path)?;
       let mut decompressor = xz2::read::XzDecoder::new(reader);
       std::io::copy(&mut decompressor, &mut writer)?;
-      writer.sync_all()?;
-      fs::remove_file(read_path)?;
-    } else if fmt == file_format::FileFormat::Zstandard {
+    }
+    file_format::FileFormat::Zstandard => {
       // .zst
-      let reader = fs::File::open(read_path)?;
-      let mut writer = fs::File::create(write_path)?;
       let mut decompressor = zstd::Decoder::new(reader)?;
       std::io::copy(&mut decompressor, &mut writer)?;
-      writer.sync_all()?;
-      fs::remove_file(read_path)?;
+    }
+    _ => return Err(format!("unsupported format: {}", fmt.name()).into()),
+  }
+  writer.sync_all()?;
+  Ok(())
+}
+
+// file_format can misdetect unusual content, so every decoder is tried before giving up
+fn decompress_any(
+  read_path: &Path,
+  write_path: &Path,
+  skip: Option<&file_format::FileFormat>,
+) -> Result<(), DbError> {
+  for fmt in DECODERS.iter() {
+    if skip == Some(fmt) {
+      continue;
+    }
+    if decompress(fmt, read_path, write_path).is_ok() {
+      info!("Extracted the database using the {} decoder", fmt.name());
+      return Ok(());
+    }
+  }
+  return Err(DbError::Extract(
+    "the database could not be extracted with any of the supported formats".to_string(),
+  ));
+}
+
+// A misbehaving mirror may serve an old database, which shouldn't replace a newer one
+fn check_not_older(metadata: &maxminddb::Metadata, destination_path: &Path) -> Result<(), DbError> {
+  let current = match maxminddb::Reader::open_mmap(destination_path) {
+    Ok(reader) => reader,
+    Err(_) => return Ok(()),
+  };
+  if metadata.build_epoch < current.metadata.build_epoch {
+    return Err(DbError::NotNewer(format!(
+      "The downloaded database (build_epoch {}) is older than the current database (build_epoch {}), keeping the current database",
+      metadata.build_epoch, current.metadata.build_epoch
+    )));
+  }
+  return Ok(());
+}
+
+fn save_mmdb(source_path: &Path, temp_path: &Path, destination_path: &Path) -> Result<(), DbError> {
+  // This function pulls out the mmdb file from a bunch of possible compression formats, even combinations that are unlikely
+  // So it needs two temporary files to do this without putting everything in memory
+  // At the end the mmdb file is moved to the destination path, in an atomic operation
+  let mut read_path = source_path;
+  let mut write_path = temp_path;
+
+  loop {
+    let fmt = file_format::FileFormat::from_file(read_path)?;
+
+    if is_compressed(&fmt) {
+      if let Err(err) = decompress(&fmt, read_path, write_path) {
+        warn!("Error extracting the database as {}: {}", fmt.name(), err);
+        if let Err(err) = decompress_any(read_path, write_path, Some(&fmt)) {
+          remove_temp_files(read_path, write_path)?;
+          return Err(err);
+        }
+      }
     } else {
-      break;
+      // verify that the database can be opened successfully
+      match maxminddb::Reader::open_mmap(read_path) {
+        Ok(reader) => {
+          debug!("{:?}", reader.metadata);
+          if let Err(err) = check_not_older(&reader.metadata, destination_path) {
+            fs::remove_file(read_path)?;
+            return Err(err);
+          }
+          break;
+        }
+        Err(err) => {
+          if decompress_any(read_path, write_path, None).is_err() {
+            remove_temp_files(read_path, write_path)?;
+            return Err(DbError::Validate(format!(
+              "Error opening newly downloaded database: {}",
+              err
+            )));
+          }
+        }
+      }
     }
 
+    fs::remove_file(read_path)?;
     std::mem::swap(&mut read_path, &mut write_path);
   }
 
-  // verify that the database can be opened successfully
-  match maxminddb::Reader::open_mmap(read_path) {
-    Ok(reader) => {
-      debug!("{:?}", reader.metadata);
-    }
-    Err(err) => {
+  move_into_place(read_path, destination_path)?;
+  return Ok(());
+}
+
+// The failed decoders may have left a partially written file behind
+fn remove_temp_files(read_path: &Path, write_path: &Path) -> std::io::Result<()> {
+  fs::remove_file(read_path)?;
+  match fs::remove_file(write_path) {
+    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+    _ => return Ok(()),
+  }
+}
+
+fn is_compressed(fmt: &file_format::FileFormat) -> bool {
+  return DECODERS.contains(fmt);
+}
+
+fn move_into_place(read_path: &Path, destination_path: &Path) -> std::io::Result<()> {
+  match fs::rename(read_path, destination_path) {
+    Ok(_) => {}
+    Err(err) if err.raw_os_error() == Some(EXDEV) => {
+      // TEMP_DIR is on a different filesystem, so copy the file next to the destination first to keep the final rename atomic
+      let staging_path = destination_path.with_extension("mmdb.temp");
+      fs::copy(read_path, &staging_path)?;
       fs::remove_file(read_path)?;
-      return Err(format!("Error opening newly downloaded database: {}", err).into());
+      fs::rename(&staging_path, destination_path)?;
     }
+    Err(err) => return Err(err),
   }
-
-  fs::rename(read_path, destination_path)?;
   Ok(())
 }
 
 fn build_reqwest_client() -> Result<reqwest::Client, reqwest::Error> {
-  let mut builder = reqwest::Client::builder();
+  let config = config::get();
+  // The timeout applies to connecting and to each read rather than the whole download, so a slow but steady download of a large database isn't cut off
+  let mut builder = reqwest::Client::builder()
+    .user_agent(&config.download_user_agent)
+    .connect_timeout(config.maxmind_db_timeout)
+    .read_timeout(config.maxmind_db_timeout);
+
+  // reqwest uses HTTP_PROXY, HTTPS_PROXY, and NO_PROXY automatically, DOWNLOAD_PROXY overrides them
+  if let Some(v) = &config.download_proxy {
+    let proxy = reqwest::Proxy::all(v)?.no_proxy(reqwest::NoProxy::from_env());
+    builder = builder.proxy(proxy);
+  }
 
-  if let Ok(v) = env::var("CA_BUNDLE") {
+  if let Some(v) = &config.ca_bundle {
     let cert_data = std::fs::read(v).expect("error reading CA_BUNDLE file");
     let cert = reqwest::Certificate::from_pem(&cert_data)?;
     builder = builder.add_root_certificate(cert);
   }
 
-  if let Ok(v) = env::var("DANGER_ACCEPT_INVALID_CERTS") {
-    builder = builder.danger_accept_invalid_certs(v == "true" || v == "1");
+  if config.danger_accept_invalid_certs {
+    builder = builder.danger_accept_invalid_certs(true);
   }
 
   return builder.build();
 }
 
-pub async fn download_database(force: bool) -> Result<(), Box<dyn Error>> {
+pub async fn send_webhook(metadata: &maxminddb::Metadata) {
+  let url = match &config::get().webhook_url {
+    Some(v) => v,
+    None => return,
+  };
+
+  let body = serde_json::json!({
+    "build_epoch": metadata.build_epoch,
+    "database_type": metadata.database_type,
+    "timestamp": chrono::Utc::now().to_rfc3339(),
+  });
+  let client = match build_reqwest_client() {
+    Ok(client) => client,
+    Err(err) => {
+      warn!("Error sending webhook: {}", err);
+      return;
+    }
+  };
+  let result = client
+    .post(url)
+    .header("content-type", "application/json")
+    .body(body.to_string())
+    .send()
+    .await;
+  match result {
+    Ok(response) if response.status().is_success() => debug!("Sent webhook to {}", url),
+    Ok(response) => warn!(
+      "Webhook got unexpected response code: {}",
+      response.status()
+    ),
+    Err(err) => warn!("Error sending webhook: {}", err),
+  }
+}
+
+// Counters for the outcomes of database downloads
+pub struct DownloadStats {
+  pub downloads: AtomicU64,
+  pub not_modified: AtomicU64,
+  pub not_newer: AtomicU64,
+  pub download_errors: AtomicU64,
+  pub extraction_errors: AtomicU64,
+}
+
+pub fn download_stats() -> &'static DownloadStats {
+  static DOWNLOAD_STATS: DownloadStats = DownloadStats {
+    downloads: AtomicU64::new(0),
+    not_modified: AtomicU64::new(0),
+    not_newer: AtomicU64::new(0),
+    download_errors: AtomicU64::new(0),
+    extraction_errors: AtomicU64::new(0),
+  };
+  &DOWNLOAD_STATS
+}
+
+// MAXMIND_DB_URL can be a comma-separated list of URLs, and MAXMIND_DB_URL_FALLBACK adds more URLs to try
+fn database_urls() -> Vec<String> {
+  let config = config::get();
+  return [
+    &config.maxmind_db_url[..],
+    &config.maxmind_db_url_fallback[..],
+  ]
+  .concat();
+}
+
+// Removes credentials and the query string (which may contain a license key) from a URL so it can be displayed
+pub fn redact_url(url: &str) -> String {
+  match reqwest::Url::parse(url) {
+    Ok(mut url) => {
+      let _ = url.set_username("");
+      let _ = url.set_password(None);
+      url.set_query(None);
+      url.to_string()
+    }
+    Err(_) => url.to_string(),
+  }
+}
+
+// Returns the (redacted) URL that the current database was downloaded from
+pub fn database_source() -> Option<String> {
+  let source = fs::read_to_string(sidecar_path("source")).ok()?;
+  return Some(redact_url(&source));
+}
+
+#[tracing::instrument(err(Display))]
+pub async fn download_database(force: bool) -> Result<(), DbError> {
   let database_path = database_path();
-  let url = env::var("MAXMIND_DB_URL");
-  if url.is_err() {
+  // OFFLINE_MODE only uses the existing database and never makes any outbound requests, even if MAXMIND_DB_URL is set
+  if config::get().offline_mode {
     if database_path.is_file() {
       return Ok(());
-    } else {
-      error!(
-        "Please configure MAXMIND_DB_URL or place a database file at {}",
+    }
+    return Err(DbError::Io(std::io::Error::new(
+      std::io::ErrorKind::NotFound,
+      format!(
+        "OFFLINE_MODE is enabled but there is no database file at {}",
         database_path.display()
-      );
-      process::exit(1);
+      ),
+    )));
+  }
+
+  let urls = database_urls();
+  if urls.is_empty() {
+    if database_path.is_file() {
+      return Ok(());
     }
+    return Err(DbError::NotConfigured(database_path.to_path_buf()));
   }
 
-  let url = url.unwrap();
-  let stamp_path = Path::new(data_dir()).join("stamp");
+  if database_path.is_file() && !data_dir_writable() {
+    info!(
+      "Using the existing database since the data directory is read-only, skipping update check"
+    );
+    return Ok(());
+  }
+
+  let stamp_path = sidecar_path("stamp");
 
   // Skip check if we have a downloaded database already and it has been less than 24 hours since the last check
-  if !force && database_path.is_file() && stamp_path.is_file() {
+  // MAXMIND_DB_NO_CACHE always does a full download, without skipping recent checks or sending conditional requests
+  if !force && !config::get().maxmind_db_no_cache && database_path.is_file() && stamp_path.is_file()
+  {
     if let Ok(metadata) = fs::metadata(&stamp_path) {
       let modified_date = metadata
         .modified()
@@ -220,11 +609,127 @@ pub async fn download_database(force: bool) -> Result<(), Box<dyn Error>> {
     }
   }
 
-  let mut request = build_reqwest_client()?.get(&url);
-  let etag_path = Path::new(data_dir()).join("etag");
-  if database_path.is_file() && etag_path.is_file() {
-    if let Ok(etag) = fs::read_to_string(&etag_path) {
+  // Try each URL in order until one succeeds
+  // Every failure is logged, and the last one is returned if they all fail
+  let stats = download_stats();
+  let mut last_error = None;
+  for (i, url) in urls.iter().enumerate() {
+    let result = download_database_from(url, i == 0).await;
+    let counter = match &result {
+      Ok(_) => Some(&stats.downloads),
+      Err(DbError::NotModified) => Some(&stats.not_modified),
+      Err(DbError::NotNewer(_)) => Some(&stats.not_newer),
+      Err(DbError::Network(_)) => Some(&stats.download_errors),
+      Err(DbError::Extract(_) | DbError::Validate(_)) => Some(&stats.extraction_errors),
+      Err(DbError::NotConfigured(_) | DbError::Io(_)) => None,
+    };
+    if let Some(counter) = counter {
+      counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    match result {
+      Ok(_) | Err(DbError::NotModified) => return Ok(()),
+      Err(err) => {
+        warn!(
+          "Error downloading database from {}: {}",
+          redact_url(url),
+          err
+        );
+        last_error = Some(err);
+      }
+    }
+  }
+
+  if database_path.is_file() {
+    match fs::metadata(database_path) {
+      Ok(metadata) => {
+        let modified_date = metadata
+          .modified()
+          .expect("error getting database last modified date");
+        let duration_since = time::SystemTime::now()
+          .duration_since(modified_date)
+          .expect("error calculating time duration since database last modified date");
+        let formatter = timeago::Formatter::new();
+        let formatted_time = formatter.convert(duration_since);
+        info!(
+          "There is a database saved from {} so ignoring the error",
+          formatted_time
+        );
+        return Ok(());
+      }
+      Err(err) => {
+        return Err(DbError::Io(err));
+      }
+    }
+  }
+
+  return Err(last_error.expect("there is at least one URL"));
+}
+
+// Returns the ETag in its canonical form, either "tag" or W/"tag", adding the quotes if a server left them out
+// Returns None for values that can't be sent back in If-None-Match
+fn normalize_etag(etag: &str) -> Option<String> {
+  let etag = etag.trim();
+  let (weak, tag) = match etag.strip_prefix("W/") {
+    Some(tag) => (true, tag),
+    None => (false, etag),
+  };
+  let tag = match tag.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+    Some(tag) => tag,
+    None => tag,
+  };
+  // The tag may not be empty or contain quotes, spaces, or control characters
+  if tag.is_empty()
+    || tag
+      .chars()
+      .any(|c| c == '"' || c.is_whitespace() || c.is_control())
+  {
+    return None;
+  }
+  return Some(format!(r#"{}"{}""#, if weak { "W/" } else { "" }, tag));
+}
+
+fn save_validators(etag: Option<String>, last_modified: Option<String>) -> std::io::Result<()> {
+  let etag_path = sidecar_path("etag");
+  let last_modified_path = sidecar_path("last-modified");
+
+  if let Some(etag) = etag {
+    fs::write(etag_path, etag)?;
+  } else if etag_path.is_file() {
+    fs::remove_file(etag_path)?;
+  }
+
+  if let Some(last_modified) = last_modified {
+    fs::write(last_modified_path, last_modified)?;
+  } else if last_modified_path.is_file() {
+    fs::remove_file(last_modified_path)?;
+  }
+  return Ok(());
+}
+
+async fn download_database_from(url: &str, primary: bool) -> Result<(), DbError> {
+  let database_path = database_path();
+  let stamp_path = sidecar_path("stamp");
+  let etag_path = sidecar_path("etag");
+  let last_modified_path = sidecar_path("last-modified");
+  let source_path = sidecar_path("source");
+
+  // Only send the ETag (or Last-Modified date if there's no ETag) back to the URL that it came from
+  // Databases downloaded before the source file existed came from the primary URL
+  let same_source = match fs::read_to_string(&source_path) {
+    Ok(source) => source == url,
+    Err(_) => primary,
+  };
+
+  let mut request = build_reqwest_client()?.get(url);
+  if same_source && !config::get().maxmind_db_no_cache && database_path.is_file() {
+    let etag = fs::read_to_string(&etag_path)
+      .ok()
+      .and_then(|v| normalize_etag(&v));
+    if let Some(etag) = etag {
       request = request.header("If-None-Match", etag);
+    } else if let Ok(last_modified) = fs::read_to_string(&last_modified_path) {
+      request = request.header("If-Modified-Since", last_modified);
     }
   }
   let response = request.send().await?;
@@ -233,64 +738,298 @@ pub async fn download_database(force: bool) -> Result<(), Box<dyn Error>> {
   if status_code == reqwest::StatusCode::NOT_MODIFIED {
     info!("The database file is up to date");
     fs::write(stamp_path, "")?;
-    return Ok(());
+    return Err(DbError::NotModified);
   } else if status_code != reqwest::StatusCode::OK {
-    if database_path.is_file() {
-      warn!("Got unexpected response code: {}", status_code);
-      match fs::metadata(database_path) {
-        Ok(metadata) => {
-          let modified_date = metadata
-            .modified()
-            .expect("error getting database last modified date");
-          let duration_since = time::SystemTime::now()
-            .duration_since(modified_date)
-            .expect("error calculating time duration since database last modified date");
-          let formatter = timeago::Formatter::new();
-          let formatted_time = formatter.convert(duration_since);
-          info!(
-            "There is a database saved from {} so ignoring the error",
-            formatted_time
-          );
-          return Ok(());
-        }
-        Err(err) => {
-          return Err(format!("Error: {:?}: {}", &database_path, err).into());
-        }
-      }
-    } else {
-      return Err(format!("Got unexpected response code: {}", status_code).into());
-    }
+    return Err(DbError::Network(format!(
+      "Got unexpected response code: {}",
+      status_code
+    )));
   }
 
   let etag = response
     .headers()
     .get("ETag")
+    .and_then(|v| v.to_str().ok())
+    .and_then(normalize_etag);
+  let last_modified = response
+    .headers()
+    .get("Last-Modified")
     .and_then(|v| v.to_str().ok().map(|v| v.to_string()));
 
-  let temp_path = Path::new(data_dir()).join("database.mmdb.temp");
-  let temp_path2 = Path::new(data_dir()).join("database.mmdb.temp2");
+  let temp_path = temp_dir().join(format!("{}database.mmdb.temp", sidecar_prefix()));
+  let temp_path2 = temp_dir().join(format!("{}database.mmdb.temp2", sidecar_prefix()));
+
+  // Stream the body to the temp file so that large databases don't have to fit in memory
+  let content_length = response.content_length();
   let mut temp_file = fs::File::create(&temp_path)?;
-  let mut reader = response.bytes().await?.reader();
-  // why does this copy require a trait from actix_web??
-  std::io::copy(&mut reader, &mut temp_file)?;
+  let mut stream = response.bytes_stream();
+  let mut downloaded: u64 = 0;
+  let mut last_progress = time::Instant::now();
+  while let Some(chunk) = stream.next().await {
+    let chunk = chunk?;
+    temp_file.write_all(&chunk)?;
+    downloaded += chunk.len() as u64;
+    if last_progress.elapsed() >= time::Duration::from_secs(10) {
+      last_progress = time::Instant::now();
+      match content_length {
+        Some(total) => info!(
+          "Downloaded {} of {} MB",
+          downloaded / 1_000_000,
+          total / 1_000_000
+        ),
+        None => info!("Downloaded {} MB", downloaded / 1_000_000),
+      }
+    }
+  }
   temp_file.sync_all()?;
+  drop(temp_file);
+  debug!("Downloaded {} bytes", downloaded);
 
-  if let Err(err) = save_mmdb(&temp_path, &temp_path2, database_path) {
-    if database_path.is_file() {
-      warn!("{}", err);
-      return Ok(());
-    } else {
+  // Extracting and validating the database is blocking work, so it runs on the blocking thread pool
+  let result =
+    tokio::task::spawn_blocking(move || save_mmdb(&temp_path, &temp_path2, database_path))
+      .await
+      .map_err(|err| DbError::Io(std::io::Error::other(err)))?;
+  match result {
+    Ok(_) => {}
+    // The older database is discarded, but the check is still recorded so that the same file isn't downloaded again on every update check
+    // The ETag and Last-Modified date are only kept if the current database came from this URL, since they are sent back to the source URL
+    Err(err @ DbError::NotNewer(_)) => {
+      if same_source {
+        save_validators(etag, last_modified)?;
+      }
+      fs::write(stamp_path, "")?;
       return Err(err);
     }
+    Err(err) => return Err(err),
   }
 
-  if let Some(etag) = etag {
-    fs::write(etag_path, etag)?;
-  }
-
+  save_validators(etag, last_modified)?;
+  fs::write(source_path, url)?;
   fs::write(stamp_path, "")?;
 
-  info!("Downloaded a database");
+  info!("Downloaded a database from {}", redact_url(url));
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+
+  fn fixture() -> Vec<u8> {
+    return fs::read(
+      Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/GeoIP2-City-Test.mmdb"),
+    )
+    .expect("error reading the fixture");
+  }
+
+  // save_mmdb works with fixed file names, so every test gets its own directory, which is removed when the guard is dropped (also when the test fails)
+  struct TestDir(PathBuf);
+
+  impl TestDir {
+    fn new(name: &str) -> TestDir {
+      let dir = env::temp_dir().join(format!("maxmind-geoip-api-test-{}-{}", process::id(), name));
+      let _ = fs::remove_dir_all(&dir);
+      fs::create_dir_all(&dir).unwrap();
+      return TestDir(dir);
+    }
+  }
+
+  impl Drop for TestDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn save_fixture(name: &str, download: Vec<u8>) -> Vec<u8> {
+    let test_dir = TestDir::new(name);
+    let dir = &test_dir.0;
+    let source_path = dir.join("download");
+    let destination_path = dir.join("database.mmdb");
+    fs::write(&source_path, download).unwrap();
+    save_mmdb(&source_path, &dir.join("download.temp"), &destination_path)
+      .expect("error saving the database");
+    return fs::read(&destination_path).unwrap();
+  }
+
+  fn tar(data: &[u8]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+      .append_data(
+        &mut header,
+        "GeoIP2-City-Test_20231114/GeoIP2-City-Test.mmdb",
+        data,
+      )
+      .unwrap();
+    return builder.into_inner().unwrap();
+  }
+
+  fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    return encoder.finish().unwrap();
+  }
+
+  fn bzip2(data: &[u8]) -> Vec<u8> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    encoder.write_all(data).unwrap();
+    return encoder.finish().unwrap();
+  }
+
+  fn xz(data: &[u8]) -> Vec<u8> {
+    let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+    encoder.write_all(data).unwrap();
+    return encoder.finish().unwrap();
+  }
+
+  fn zst(data: &[u8]) -> Vec<u8> {
+    return zstd::encode_all(data, 0).unwrap();
+  }
+
+  // Archives made on macOS have an AppleDouble file next to each file, which has to be skipped
+  fn zip(data: &[u8]) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    writer
+      .start_file("__MACOSX/._GeoIP2-City-Test.mmdb", options)
+      .unwrap();
+    writer.write_all(b"not a database").unwrap();
+    writer.start_file("GeoIP2-City-Test.mmdb", options).unwrap();
+    writer.write_all(data).unwrap();
+    return writer.finish().unwrap().into_inner();
+  }
+
+  #[test]
+  fn save_mmdb_uncompressed() {
+    assert_eq!(save_fixture("mmdb", fixture()), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_tar() {
+    assert_eq!(save_fixture("tar", tar(&fixture())), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_tar_gz() {
+    assert_eq!(save_fixture("tar-gz", gzip(&tar(&fixture()))), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_zip() {
+    assert_eq!(save_fixture("zip", zip(&fixture())), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_gz() {
+    assert_eq!(save_fixture("gz", gzip(&fixture())), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_xz() {
+    assert_eq!(save_fixture("xz", xz(&fixture())), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_zst() {
+    assert_eq!(save_fixture("zst", zst(&fixture())), fixture());
+  }
+
+  #[test]
+  fn save_mmdb_bz2() {
+    assert_eq!(save_fixture("bz2", bzip2(&fixture())), fixture());
+  }
+
+  // Parallel compressors like pbzip2 write one bzip2 stream per block, and all of them have to be read
+  #[test]
+  fn save_mmdb_bz2_multi_stream() {
+    let data = fixture();
+    let (first, second) = data.split_at(data.len() / 2);
+    let mut download = bzip2(first);
+    download.extend(bzip2(second));
+    assert_eq!(save_fixture("bz2-multi-stream", download), data);
+  }
+
+  #[test]
+  fn save_mmdb_invalid() {
+    let test_dir = TestDir::new("invalid");
+    let dir = &test_dir.0;
+    let source_path = dir.join("download");
+    fs::write(&source_path, b"not a database").unwrap();
+    let result = save_mmdb(
+      &source_path,
+      &dir.join("download.temp"),
+      &dir.join("database.mmdb"),
+    );
+    assert!(matches!(result, Err(DbError::Validate(_))));
+    assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+  }
+
+  #[test]
+  fn save_mmdb_truncated() {
+    let test_dir = TestDir::new("truncated");
+    let dir = &test_dir.0;
+    let source_path = dir.join("download");
+    fs::write(&source_path, &gzip(&fixture())[..100]).unwrap();
+    let result = save_mmdb(
+      &source_path,
+      &dir.join("download.temp"),
+      &dir.join("database.mmdb"),
+    );
+    assert!(matches!(result, Err(DbError::Extract(_))));
+    assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+  }
+
+  #[test]
+  fn save_mmdb_older() {
+    let test_dir = TestDir::new("older");
+    let dir = &test_dir.0;
+    // The current database is the fixture with build_epoch 1700000001, encoded as a 4-byte uint64
+    let mut newer = fixture();
+    let epoch = b"build_epoch\x04\x02\x65\x53\xf1\x00";
+    let i = newer
+      .windows(epoch.len())
+      .rposition(|window| window == epoch)
+      .unwrap();
+    newer[i + epoch.len() - 1] = 0x01;
+    fs::write(dir.join("database.mmdb"), &newer).unwrap();
+
+    let source_path = dir.join("download");
+    fs::write(&source_path, fixture()).unwrap();
+    let result = save_mmdb(
+      &source_path,
+      &dir.join("download.temp"),
+      &dir.join("database.mmdb"),
+    );
+    assert!(matches!(result, Err(DbError::NotNewer(_))));
+    assert!(!source_path.exists());
+    assert_eq!(fs::read(dir.join("database.mmdb")).unwrap(), newer);
+  }
+
+  #[test]
+  fn normalize_etag_strong() {
+    assert_eq!(normalize_etag(r#""x""#), Some(r#""x""#.to_string()));
+  }
+
+  #[test]
+  fn normalize_etag_weak() {
+    assert_eq!(normalize_etag(r#"W/"x""#), Some(r#"W/"x""#.to_string()));
+  }
+
+  #[test]
+  fn normalize_etag_unquoted() {
+    assert_eq!(normalize_etag("x"), Some(r#""x""#.to_string()));
+  }
+
+  #[test]
+  fn normalize_etag_invalid() {
+    assert_eq!(normalize_etag(""), None);
+    assert_eq!(normalize_etag(r#""""#), None);
+    assert_eq!(normalize_etag(r#""a b""#), None);
+  }
+}
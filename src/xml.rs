@@ -0,0 +1,111 @@
+use serde_json::Value;
+
+// Converts a JSON value to XML, with object keys as element names (sorted) and array elements wrapped in <item>
+// Null values are left out
+pub fn to_xml(root: &str, value: &Value) -> String {
+  let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+  write_element(&mut xml, root, value);
+  return xml;
+}
+
+fn write_element(xml: &mut String, name: &str, value: &Value) {
+  // Keys that aren't valid element names (e.g. from OUTPUT_TEMPLATE) are written as <entry key="..."> instead
+  let (start_tag, end_tag) = if is_name(name) {
+    (format!("<{}>", name), format!("</{}>", name))
+  } else {
+    (
+      format!(r#"<entry key="{}">"#, escape(name)),
+      "</entry>".to_string(),
+    )
+  };
+  match value {
+    Value::Null => {}
+    Value::Object(map) => {
+      // serde_json::Map is a BTreeMap, so the keys are already sorted
+      xml.push_str(&start_tag);
+      for (key, value) in map {
+        write_element(xml, key, value);
+      }
+      xml.push_str(&end_tag);
+    }
+    Value::Array(array) => {
+      xml.push_str(&start_tag);
+      for value in array {
+        write_element(xml, "item", value);
+      }
+      xml.push_str(&end_tag);
+    }
+    Value::String(v) => {
+      xml.push_str(&format!("{}{}{}", start_tag, escape(v), end_tag));
+    }
+    v => {
+      xml.push_str(&format!("{}{}{}", start_tag, v, end_tag));
+    }
+  }
+}
+
+// The Name production from the XML 1.0 spec, without colons since they would be read as namespace prefixes
+fn is_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  return chars.next().is_some_and(is_name_start_char) && chars.all(is_name_char);
+}
+
+fn is_name_start_char(c: char) -> bool {
+  return matches!(c,
+    'A'..='Z'
+    | '_'
+    | 'a'..='z'
+    | '\u{C0}'..='\u{D6}'
+    | '\u{D8}'..='\u{F6}'
+    | '\u{F8}'..='\u{2FF}'
+    | '\u{370}'..='\u{37D}'
+    | '\u{37F}'..='\u{1FFF}'
+    | '\u{200C}'..='\u{200D}'
+    | '\u{2070}'..='\u{218F}'
+    | '\u{2C00}'..='\u{2FEF}'
+    | '\u{3001}'..='\u{D7FF}'
+    | '\u{F900}'..='\u{FDCF}'
+    | '\u{FDF0}'..='\u{FFFD}'
+    | '\u{10000}'..='\u{EFFFF}'
+  );
+}
+
+fn is_name_char(c: char) -> bool {
+  return is_name_start_char(c)
+    || matches!(c,
+      '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}'
+    );
+}
+
+fn escape(s: &str) -> String {
+  return s
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&apos;");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn to_xml_elements() {
+    let value = json!({ "b": [1, "x & y"], "a": { "c": true, "d": null } });
+    assert_eq!(
+      to_xml("city", &value),
+      r#"<?xml version="1.0" encoding="UTF-8"?><city><a><c>true</c></a><b><item>1</item><item>x &amp; y</item></b></city>"#
+    );
+  }
+
+  #[test]
+  fn to_xml_invalid_names() {
+    let value = json!({ "1st": 1, "a b": 2, "<x>": 3, "ns:name": 4, "": 5, "é.ok-1": 6 });
+    assert_eq!(
+      to_xml("city", &value),
+      r#"<?xml version="1.0" encoding="UTF-8"?><city><entry key="">5</entry><entry key="1st">1</entry><entry key="&lt;x&gt;">3</entry><entry key="a b">2</entry><entry key="ns:name">4</entry><é.ok-1>6</é.ok-1></city>"#
+    );
+  }
+}
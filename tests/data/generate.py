@@ -0,0 +1,209 @@
+#!/usr/bin/env python3
+# Generates GeoIP2-City-Test.mmdb, a tiny database in the GeoIP2 City layout for the tests
+# The records are modelled on MaxMind's own test data but are not a copy of it
+# Run it from this directory: python3 generate.py
+
+import ipaddress
+import struct
+
+RECORD_SIZE = 24
+BUILD_EPOCH = 1700000000
+
+EUROPE = {"code": "EU", "geoname_id": 6255148, "names": {"de": "Europa", "en": "Europe"}}
+ASIA = {"code": "AS", "geoname_id": 6255147, "names": {"de": "Asien", "en": "Asia"}}
+NORTH_AMERICA = {"code": "NA", "geoname_id": 6255149, "names": {"de": "Nordamerika", "en": "North America"}}
+
+UNITED_KINGDOM = {"geoname_id": 2635167, "iso_code": "GB", "names": {"de": "Vereinigtes Königreich", "en": "United Kingdom"}}
+SWEDEN = {"geoname_id": 2661886, "is_in_european_union": True, "iso_code": "SE", "names": {"de": "Schweden", "en": "Sweden"}}
+JAPAN = {"geoname_id": 1861060, "iso_code": "JP", "names": {"de": "Japan", "en": "Japan"}}
+UNITED_STATES = {"geoname_id": 6252001, "iso_code": "US", "names": {"de": "USA", "en": "United States"}}
+
+RECORDS = [
+  (
+    "81.2.69.142/31",
+    {
+      "city": {"geoname_id": 2643743, "names": {"de": "London", "en": "London"}},
+      "continent": EUROPE,
+      "country": UNITED_KINGDOM,
+      "location": {"accuracy_radius": 10, "latitude": 51.5142, "longitude": -0.0931, "time_zone": "Europe/London"},
+      "registered_country": UNITED_KINGDOM,
+      "subdivisions": [{"geoname_id": 6269131, "iso_code": "ENG", "names": {"en": "England"}}],
+    },
+  ),
+  (
+    "2.125.160.216/29",
+    {
+      "city": {"geoname_id": 2655045, "names": {"en": "Boxford"}},
+      "continent": EUROPE,
+      "country": UNITED_KINGDOM,
+      "location": {"accuracy_radius": 100, "latitude": 51.75, "longitude": -1.25, "time_zone": "Europe/London"},
+      "postal": {"code": "OX1"},
+      "registered_country": SWEDEN,
+      "subdivisions": [
+        {"geoname_id": 6269131, "iso_code": "ENG", "names": {"en": "England"}},
+        {"geoname_id": 3333217, "iso_code": "WBK", "names": {"en": "West Berkshire"}},
+      ],
+    },
+  ),
+  (
+    "89.160.20.112/28",
+    {
+      "city": {"geoname_id": 2694762, "names": {"de": "Linköping", "en": "Linköping"}},
+      "continent": EUROPE,
+      "country": SWEDEN,
+      "location": {"accuracy_radius": 76, "latitude": 58.4167, "longitude": 15.6167, "time_zone": "Europe/Stockholm"},
+      "registered_country": SWEDEN,
+      "subdivisions": [{"geoname_id": 2685867, "iso_code": "E", "names": {"de": "Östergötland", "en": "Östergötland County"}}],
+    },
+  ),
+  (
+    "216.160.83.56/29",
+    {
+      "city": {"geoname_id": 5803556, "names": {"en": "Milton"}},
+      "continent": NORTH_AMERICA,
+      "country": UNITED_STATES,
+      "location": {"accuracy_radius": 22, "latitude": 47.2513, "longitude": -122.3149, "metro_code": 819, "time_zone": "America/Los_Angeles"},
+      "postal": {"code": "98354"},
+      "registered_country": UNITED_STATES,
+      "subdivisions": [{"geoname_id": 5815135, "iso_code": "WA", "names": {"en": "Washington"}}],
+    },
+  ),
+  (
+    "2001:218::/32",
+    {
+      "continent": ASIA,
+      "country": JAPAN,
+      "location": {"accuracy_radius": 100, "latitude": 35.68536, "longitude": 139.75309, "time_zone": "Asia/Tokyo"},
+      "registered_country": JAPAN,
+    },
+  ),
+  # Only the continent is known, e.g. for address space that is assigned to a regional registry
+  (
+    "2a02:cf40::/29",
+    {
+      "continent": EUROPE,
+    },
+  ),
+]
+
+METADATA = {
+  "binary_format_major_version": 2,
+  "binary_format_minor_version": 0,
+  "build_epoch": BUILD_EPOCH,
+  "database_type": "GeoIP2-City",
+  "description": {"en": "GeoIP2 City test database for maxmind-geoip-api"},
+  "ip_version": 6,
+  "languages": ["de", "en"],
+  "node_count": None,
+  "record_size": RECORD_SIZE,
+}
+
+
+def encode_control(type_number, size):
+  if size < 29:
+    size_bits, extra = size, b""
+  elif size < 285:
+    size_bits, extra = 29, bytes([size - 29])
+  elif size < 65821:
+    size_bits, extra = 30, struct.pack(">H", size - 285)
+  else:
+    size_bits, extra = 31, struct.pack(">I", size - 65821)[1:]
+  if type_number <= 7:
+    return bytes([(type_number << 5) | size_bits]) + extra
+  return bytes([size_bits, type_number - 7]) + extra
+
+
+def encode_uint(type_number, value):
+  data = value.to_bytes((value.bit_length() + 7) // 8, "big") if value else b""
+  return encode_control(type_number, len(data)) + data
+
+
+def encode(value, type_hint=None):
+  if isinstance(value, bool):
+    return encode_control(14, int(value))
+  if isinstance(value, str):
+    data = value.encode("utf-8")
+    return encode_control(2, len(data)) + data
+  if isinstance(value, float):
+    return encode_control(3, 8) + struct.pack(">d", value)
+  if isinstance(value, int):
+    return encode_uint(type_hint or 6, value)
+  if isinstance(value, dict):
+    result = encode_control(7, len(value))
+    for key, item in value.items():
+      result += encode(key) + encode(item, UINT_TYPES.get(key))
+    return result
+  if isinstance(value, list):
+    return encode_control(11, len(value)) + b"".join(encode(item) for item in value)
+  raise TypeError(value)
+
+
+# Integer fields that are not uint32 in the GeoIP2 and metadata layouts
+UINT_TYPES = {
+  "accuracy_radius": 5,
+  "binary_format_major_version": 5,
+  "binary_format_minor_version": 5,
+  "build_epoch": 9,
+  "ip_version": 5,
+  "metro_code": 5,
+  "record_size": 5,
+}
+
+
+def network_bits(network):
+  network = ipaddress.ip_network(network)
+  if network.version == 4:
+    # IPv4 networks live in the ::/96 subtree of an IPv6 database
+    address = int(network.network_address)
+    prefix = 96 + network.prefixlen
+  else:
+    address = int(network.network_address)
+    prefix = network.prefixlen
+  return [(address >> (127 - i)) & 1 for i in range(prefix)]
+
+
+def main():
+  # Each node is a pair of records, either ("node", index), ("data", offset) or None
+  nodes = [[None, None]]
+  data = b""
+  for network, record in RECORDS:
+    offset = len(data)
+    data += encode(record)
+    bits = network_bits(network)
+    node = 0
+    for bit in bits[:-1]:
+      child = nodes[node][bit]
+      if child is None:
+        nodes.append([None, None])
+        child = ("node", len(nodes) - 1)
+        nodes[node][bit] = child
+      assert child[0] == "node", f"{network} overlaps another network"
+      node = child[1]
+    assert nodes[node][bits[-1]] is None, f"{network} overlaps another network"
+    nodes[node][bits[-1]] = ("data", offset)
+
+  node_count = len(nodes)
+
+  def record_value(record):
+    if record is None:
+      return node_count
+    kind, value = record
+    if kind == "node":
+      return value
+    return node_count + 16 + value
+
+  tree = b""
+  for left, right in nodes:
+    tree += record_value(left).to_bytes(3, "big") + record_value(right).to_bytes(3, "big")
+
+  metadata = dict(METADATA, node_count=node_count)
+  with open("GeoIP2-City-Test.mmdb", "wb") as f:
+    f.write(tree)
+    f.write(b"\x00" * 16)
+    f.write(data)
+    f.write(b"\xab\xcd\xefMaxMind.com")
+    f.write(encode(metadata))
+
+
+if __name__ == "__main__":
+  main()